@@ -0,0 +1,205 @@
+// Copyright © 2024 The Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Number of device slots on a single PCI bus (5 bits of device number).
+pub const PCI_DEVICES_PER_BUS: u32 = 32;
+/// Number of functions a single PCI device slot can expose (3 bits of
+/// function number).
+pub const PCI_FUNCTIONS_PER_DEVICE: u32 = 8;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("PCI device number {0} is out of range (0..{PCI_DEVICES_PER_BUS})")]
+    DeviceOutOfRange(u32),
+    #[error("PCI function number {0} is out of range (0..{PCI_FUNCTIONS_PER_DEVICE})")]
+    FunctionOutOfRange(u32),
+    #[error("Invalid PCI address: {0}")]
+    InvalidAddress(String),
+}
+
+/// A PCI "bus:device.function" address uniquely identifying a device, or a
+/// function of a multi-function device, on the PCI topology.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PciAddress {
+    pub bus: u8,
+    /// 5 bits: 0..=31
+    pub dev: u8,
+    /// 3 bits: 0..=7
+    pub func: u8,
+}
+
+impl PciAddress {
+    pub fn new(bus: u32, dev: u32, func: u32) -> Result<Self, Error> {
+        if dev >= PCI_DEVICES_PER_BUS {
+            return Err(Error::DeviceOutOfRange(dev));
+        }
+        if func >= PCI_FUNCTIONS_PER_DEVICE {
+            return Err(Error::FunctionOutOfRange(func));
+        }
+
+        Ok(PciAddress {
+            bus: bus as u8,
+            dev: dev as u8,
+            func: func as u8,
+        })
+    }
+
+    /// Decode a `(PciAddress, register)` pair from a legacy 0xCF8
+    /// CONFIG_ADDRESS value, as used by the port I/O configuration
+    /// mechanism.
+    pub fn from_cam_register(config_address: u32) -> (PciAddress, usize) {
+        const BUS_NUMBER_OFFSET: usize = 16;
+        const BUS_NUMBER_MASK: u32 = 0x00ff;
+        const DEVICE_NUMBER_OFFSET: usize = 11;
+        const DEVICE_NUMBER_MASK: u32 = 0x1f;
+        const FUNCTION_NUMBER_OFFSET: usize = 8;
+        const FUNCTION_NUMBER_MASK: u32 = 0x07;
+        const REGISTER_NUMBER_OFFSET: usize = 2;
+        const REGISTER_NUMBER_MASK: u32 = 0x3f;
+
+        let address = PciAddress {
+            bus: shift_and_mask(config_address, BUS_NUMBER_OFFSET, BUS_NUMBER_MASK) as u8,
+            dev: shift_and_mask(config_address, DEVICE_NUMBER_OFFSET, DEVICE_NUMBER_MASK) as u8,
+            func: shift_and_mask(config_address, FUNCTION_NUMBER_OFFSET, FUNCTION_NUMBER_MASK)
+                as u8,
+        };
+        let register = shift_and_mask(config_address, REGISTER_NUMBER_OFFSET, REGISTER_NUMBER_MASK);
+
+        (address, register)
+    }
+
+    /// Encode this address and a config register index back into the legacy
+    /// 0xCF8 CONFIG_ADDRESS layout (the enable bit, bit 31, is not set here).
+    pub fn to_cam_register(self, register: usize) -> u32 {
+        (u32::from(self.bus) << 16)
+            | ((u32::from(self.dev) & 0x1f) << 11)
+            | ((u32::from(self.func) & 0x07) << 8)
+            | ((register as u32 & 0x3f) << 2)
+    }
+
+    /// Decode a `(PciAddress, register)` pair from an ECAM config address, as
+    /// used by the PCI Express Enhanced Configuration Access Mechanism.
+    /// See section 7.2.2 of the PCI Express Base Specification Revision 5.0.
+    pub fn from_ecam_register(config_address: u32) -> (PciAddress, usize) {
+        const BUS_NUMBER_OFFSET: usize = 20;
+        const BUS_NUMBER_MASK: u32 = 0x00ff;
+        const DEVICE_NUMBER_OFFSET: usize = 15;
+        const DEVICE_NUMBER_MASK: u32 = 0x1f;
+        const FUNCTION_NUMBER_OFFSET: usize = 12;
+        const FUNCTION_NUMBER_MASK: u32 = 0x07;
+        const REGISTER_NUMBER_OFFSET: usize = 2;
+        const REGISTER_NUMBER_MASK: u32 = 0x3ff;
+
+        let address = PciAddress {
+            bus: shift_and_mask(config_address, BUS_NUMBER_OFFSET, BUS_NUMBER_MASK) as u8,
+            dev: shift_and_mask(config_address, DEVICE_NUMBER_OFFSET, DEVICE_NUMBER_MASK) as u8,
+            func: shift_and_mask(config_address, FUNCTION_NUMBER_OFFSET, FUNCTION_NUMBER_MASK)
+                as u8,
+        };
+        let register = shift_and_mask(config_address, REGISTER_NUMBER_OFFSET, REGISTER_NUMBER_MASK);
+
+        (address, register)
+    }
+
+    /// Encode this address and a config register index back into the ECAM
+    /// layout.
+    pub fn to_ecam_register(self, register: usize) -> u32 {
+        (u32::from(self.bus) << 20)
+            | ((u32::from(self.dev) & 0x1f) << 15)
+            | ((u32::from(self.func) & 0x07) << 12)
+            | ((register as u32 & 0x3ff) << 2)
+    }
+}
+
+fn shift_and_mask(value: u32, offset: usize, mask: u32) -> usize {
+    ((value >> offset) & mask) as usize
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}.{:x}", self.bus, self.dev, self.func)
+    }
+}
+
+impl FromStr for PciAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (bus, rest) = s
+            .split_once(':')
+            .ok_or_else(|| Error::InvalidAddress(s.to_owned()))?;
+        let (dev, func) = rest
+            .split_once('.')
+            .ok_or_else(|| Error::InvalidAddress(s.to_owned()))?;
+
+        let bus = u8::from_str_radix(bus, 16).map_err(|_| Error::InvalidAddress(s.to_owned()))?;
+        let dev = u32::from_str_radix(dev, 16).map_err(|_| Error::InvalidAddress(s.to_owned()))?;
+        let func =
+            u32::from_str_radix(func, 16).map_err(|_| Error::InvalidAddress(s.to_owned()))?;
+
+        PciAddress::new(u32::from(bus), dev, func)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_rejects_out_of_range_device_and_function() {
+        assert!(matches!(
+            PciAddress::new(0, PCI_DEVICES_PER_BUS, 0),
+            Err(Error::DeviceOutOfRange(_))
+        ));
+        assert!(matches!(
+            PciAddress::new(0, 0, PCI_FUNCTIONS_PER_DEVICE),
+            Err(Error::FunctionOutOfRange(_))
+        ));
+        assert!(PciAddress::new(0, PCI_DEVICES_PER_BUS - 1, PCI_FUNCTIONS_PER_DEVICE - 1).is_ok());
+    }
+
+    #[test]
+    fn cam_register_round_trips() {
+        let address = PciAddress::new(0x12, 0x1f, 0x7).unwrap();
+        let register = 0x2a;
+
+        let config_address = address.to_cam_register(register);
+        assert_eq!(
+            PciAddress::from_cam_register(config_address),
+            (address, register)
+        );
+    }
+
+    #[test]
+    fn ecam_register_round_trips() {
+        let address = PciAddress::new(0xab, 0x1f, 0x7).unwrap();
+        let register = 0x3ad;
+
+        let config_address = address.to_ecam_register(register);
+        assert_eq!(
+            PciAddress::from_ecam_register(config_address),
+            (address, register)
+        );
+    }
+
+    #[test]
+    fn display_and_from_str_round_trip() {
+        let address = PciAddress::new(0x00, 0x1f, 0x3).unwrap();
+        let formatted = address.to_string();
+        assert_eq!(formatted, "0000:1f.3");
+        assert_eq!(formatted.parse::<PciAddress>().unwrap(), address);
+    }
+
+    #[test]
+    fn from_str_rejects_malformed_input() {
+        assert!("not-an-address".parse::<PciAddress>().is_err());
+        assert!("00:1f".parse::<PciAddress>().is_err());
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright © 2024 The Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::any::Any;
+use std::sync::{Arc, Barrier};
+
+use crate::configuration::{PciBridgeSubclass, PciClassCode, PciConfiguration, PciHeaderType};
+use crate::device::{BarReprogrammingParams, PciDevice};
+use crate::PciBarConfiguration;
+
+// Register index (offset 0x18) holding the primary/secondary/subordinate
+// bus-number triplet and the secondary latency timer, in that byte order.
+pub(crate) const BRIDGE_BUS_REGISTER: usize = 6;
+const PRIMARY_BUS_OFFSET: usize = 0;
+const SECONDARY_BUS_OFFSET: usize = 1;
+const SUBORDINATE_BUS_OFFSET: usize = 2;
+
+const VENDOR_ID_INTEL: u16 = 0x8086;
+const DEVICE_ID_INTEL_VIRT_PCIE_BRIDGE: u16 = 0x0d58;
+
+/// A PCI-to-PCI bridge, exposing the primary/secondary/subordinate bus
+/// number registers and the memory/prefetchable-memory windows of a type 1
+/// (bridge) header. Programming the secondary bus number is how a guest
+/// assigns a bus number to the bus living behind this bridge; callers should
+/// watch `secondary_bus()` across calls to `write_config_register` and
+/// re-key the corresponding `PciBus` when it changes.
+pub struct PciBridge {
+    config: PciConfiguration,
+}
+
+impl PciBridge {
+    pub fn new(
+        primary_bus: u8,
+        secondary_bus: u8,
+        subordinate_bus: u8,
+        bars: Option<Vec<PciBarConfiguration>>,
+    ) -> Self {
+        let mut config = PciConfiguration::new(
+            VENDOR_ID_INTEL,
+            DEVICE_ID_INTEL_VIRT_PCIE_BRIDGE,
+            0,
+            PciClassCode::BridgeDevice,
+            &PciBridgeSubclass::PciToPciBridge,
+            None,
+            PciHeaderType::Bridge,
+            0,
+            0,
+            bars,
+            None,
+        );
+
+        let bus_numbers = (u32::from(subordinate_bus) << (SUBORDINATE_BUS_OFFSET * 8))
+            | (u32::from(secondary_bus) << (SECONDARY_BUS_OFFSET * 8))
+            | (u32::from(primary_bus) << (PRIMARY_BUS_OFFSET * 8));
+        config.write_config_register(BRIDGE_BUS_REGISTER, 0, &bus_numbers.to_le_bytes());
+
+        PciBridge { config }
+    }
+
+    /// The bus number currently programmed behind this bridge.
+    pub fn secondary_bus(&self) -> u8 {
+        (self.config.read_reg(BRIDGE_BUS_REGISTER) >> (SECONDARY_BUS_OFFSET * 8)) as u8
+    }
+
+    pub fn primary_bus(&self) -> u8 {
+        (self.config.read_reg(BRIDGE_BUS_REGISTER) >> (PRIMARY_BUS_OFFSET * 8)) as u8
+    }
+
+    pub fn subordinate_bus(&self) -> u8 {
+        (self.config.read_reg(BRIDGE_BUS_REGISTER) >> (SUBORDINATE_BUS_OFFSET * 8)) as u8
+    }
+}
+
+impl PciDevice for PciBridge {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> (Vec<BarReprogrammingParams>, Option<Arc<Barrier>>) {
+        (
+            self.config.write_config_register(reg_idx, offset, data),
+            None,
+        )
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.config.read_reg(reg_idx)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn id(&self) -> Option<String> {
+        None
+    }
+}
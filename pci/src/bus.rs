@@ -5,14 +5,17 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ops::DerefMut;
 use std::sync::{Arc, Barrier, Mutex};
 
 use byteorder::{ByteOrder, LittleEndian};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use vm_device::{Bus, BusDevice, BusDeviceSync};
 
+use crate::address::{PciAddress, PCI_FUNCTIONS_PER_DEVICE};
+use crate::bridge::PciBridge;
 use crate::configuration::{
     PciBarRegionType, PciBridgeSubclass, PciClassCode, PciConfiguration, PciHeaderType,
 };
@@ -23,6 +26,23 @@ const VENDOR_ID_INTEL: u16 = 0x8086;
 const DEVICE_ID_INTEL_VIRT_PCIE_HOST: u16 = 0x0d57;
 const NUM_DEVICE_IDS: usize = 32;
 
+// Register index of the cache-line-size/latency-timer/header-type/BIST
+// register (offset 0x0c) in PCI config space.
+const PCI_CONFIG_HEADER_TYPE_REG: usize = 3;
+// Header-type register, bit 23: set when a device implements more than one
+// function.
+const HEADER_TYPE_MULTIFUNCTION_MASK: u32 = 0x0080_0000;
+// Number of 32-bit config-space registers snapshotted per device: the full
+// 4 KiB PCIe extended config space, as addressed by the ECAM mechanism.
+const PCI_CONFIG_SPACE_REGISTERS: usize = 1024;
+
+// Register index of the command/status register (offset 0x04).
+const COMMAND_REG: usize = 1;
+// Command register, bit 0: I/O space decoding enable.
+const COMMAND_REG_IO_SPACE_MASK: u32 = 0x1;
+// Command register, bit 1: memory space decoding enable.
+const COMMAND_REG_MEMORY_SPACE_MASK: u32 = 0x2;
+
 /// Errors for device manager.
 #[derive(Error, Debug)]
 pub enum PciRootError {
@@ -110,67 +130,254 @@ impl PciDevice for PciRoot {
 }
 
 pub struct PciBus {
-    /// Devices attached to this bus.
-    /// Device 0 is host bridge.
-    devices: HashMap<u32, Arc<Mutex<dyn PciDevice>>>,
+    /// Devices attached to this bus, keyed by their (bus, device, function)
+    /// address. Device 0, function 0 is the host bridge.
+    devices: HashMap<PciAddress, Arc<Mutex<dyn PciDevice>>>,
     device_reloc: Arc<dyn DeviceRelocation>,
     device_ids: Vec<bool>,
+    /// Secondary buses reachable through a `PciBridge` on this bus, keyed by
+    /// the bus number currently programmed into the bridge's secondary-bus
+    /// register. Re-keyed whenever the guest reprograms that register.
+    buses: BTreeMap<u8, Arc<Mutex<PciBus>>>,
+    /// BAR decoding state for devices registered via `register_mapping`,
+    /// gating whether their ranges are actually inserted into the io/mmio
+    /// buses on the device's COMMAND register I/O and memory space enable
+    /// bits.
+    bar_mappings: HashMap<PciAddress, PciBarMapping>,
+}
+
+/// BAR ranges and decoding state tracked for a device registered through
+/// `PciBus::register_mapping`.
+struct PciBarMapping {
+    dev: Arc<dyn BusDeviceSync>,
+    io_bus: Bus,
+    mmio_bus: Bus,
+    /// Mirrors each BAR's region type, current address and size. Tracked
+    /// separately from the `PciBarConfiguration`s passed to
+    /// `register_mapping` (rather than re-reading them) so a BAR moved by a
+    /// later config write (see `write_config_register`'s handling of
+    /// `BarReprogrammingParams`) is reflected here too; otherwise toggling
+    /// the COMMAND register after a move would insert/remove the device's
+    /// pre-move address instead of where it actually lives now.
+    bars: Vec<TrackedBar>,
+    io_enabled: bool,
+    memory_enabled: bool,
+}
+
+#[derive(Clone, Copy)]
+struct TrackedBar {
+    region_type: PciBarRegionType,
+    addr: u64,
+    size: u64,
 }
 
 impl PciBus {
     pub fn new(pci_root: PciRoot, device_reloc: Arc<dyn DeviceRelocation>) -> Self {
-        let mut devices: HashMap<u32, Arc<Mutex<dyn PciDevice>>> = HashMap::new();
+        let mut devices: HashMap<PciAddress, Arc<Mutex<dyn PciDevice>>> = HashMap::new();
         let mut device_ids: Vec<bool> = vec![false; NUM_DEVICE_IDS];
 
-        devices.insert(0, Arc::new(Mutex::new(pci_root)));
+        devices.insert(PciAddress::default(), Arc::new(Mutex::new(pci_root)));
         device_ids[0] = true;
 
         PciBus {
             devices,
             device_reloc,
             device_ids,
+            buses: BTreeMap::new(),
+            bar_mappings: HashMap::new(),
+        }
+    }
+
+    /// Attach a `PciBridge` at `address` on this bus and register the
+    /// `PciBus` living behind it, keyed by whatever secondary bus number the
+    /// bridge is currently programmed with (typically re-keyed once the
+    /// guest assigns the real bus number during enumeration).
+    pub fn add_bridge(
+        &mut self,
+        address: PciAddress,
+        bridge: Arc<Mutex<PciBridge>>,
+        secondary_bus: Arc<Mutex<PciBus>>,
+    ) -> Result<()> {
+        let secondary_bus_number = bridge.lock().unwrap().secondary_bus();
+        self.buses.insert(secondary_bus_number, secondary_bus);
+        self.add_device(address, bridge)
+    }
+
+    /// Look up the bus registered for `bus_number`, if any, through the
+    /// bridges registered with `add_bridge`. This bus itself is never
+    /// returned; `0` is looked up in `buses` like any other number. `buses`
+    /// only holds this bus's direct
+    /// children, so a number behind a grandchild bridge is found by
+    /// recursing into each child in turn.
+    fn resolve_bus(&self, bus_number: u8) -> Option<Arc<Mutex<PciBus>>> {
+        if let Some(bus) = self.buses.get(&bus_number) {
+            return Some(bus.clone());
+        }
+
+        self.buses
+            .values()
+            .find_map(|bus| bus.lock().unwrap().resolve_bus(bus_number))
+    }
+
+    /// Move the `PciBus` registered at `old_bus` (if any) to `new_bus`, used
+    /// when a bridge's secondary-bus register is reprogrammed by the guest.
+    fn rekey_bus(&mut self, old_bus: u8, new_bus: u8) {
+        if old_bus == new_bus {
+            return;
+        }
+        if let Some(bus) = self.buses.remove(&old_bus) {
+            self.buses.insert(new_bus, bus);
         }
     }
 
+    /// Register `dev`'s BARs for config-space-driven I/O and MMIO decoding.
+    /// A BAR is only actually inserted into `io_bus`/`mmio_bus` once the
+    /// device's COMMAND register enables the matching decoder (bit 0 for
+    /// I/O space, bit 1 for memory space); `address`'s current COMMAND
+    /// register value is read to decide the initial state, and subsequent
+    /// writes to that register (via `write_config_register`) insert or
+    /// remove the ranges as the guest toggles the bits.
     pub fn register_mapping(
-        &self,
+        &mut self,
+        address: PciAddress,
         dev: Arc<dyn BusDeviceSync>,
         io_bus: &Bus,
         mmio_bus: &Bus,
         bars: Vec<PciBarConfiguration>,
     ) -> Result<()> {
-        for bar in bars {
-            match bar.region_type() {
+        let command = self
+            .devices
+            .get(&address)
+            .map_or(0, |d| d.lock().unwrap().read_config_register(COMMAND_REG));
+
+        let bars = bars
+            .iter()
+            .map(|bar| TrackedBar {
+                region_type: bar.region_type(),
+                addr: bar.addr(),
+                size: bar.size(),
+            })
+            .collect();
+
+        self.bar_mappings.insert(
+            address,
+            PciBarMapping {
+                dev,
+                io_bus: io_bus.clone(),
+                mmio_bus: mmio_bus.clone(),
+                bars,
+                io_enabled: false,
+                memory_enabled: false,
+            },
+        );
+
+        self.apply_command_register(
+            address,
+            command & COMMAND_REG_IO_SPACE_MASK != 0,
+            command & COMMAND_REG_MEMORY_SPACE_MASK != 0,
+        )
+    }
+
+    /// Insert or remove `address`'s BAR ranges from the io/mmio buses to
+    /// match the decoders the guest has just enabled or disabled, and
+    /// remember the new state so the next call only touches what changed.
+    fn apply_command_register(
+        &mut self,
+        address: PciAddress,
+        io_enabled: bool,
+        memory_enabled: bool,
+    ) -> Result<()> {
+        let Some(mapping) = self.bar_mappings.get_mut(&address) else {
+            return Ok(());
+        };
+
+        for bar in &mapping.bars {
+            match bar.region_type {
                 PciBarRegionType::IoRegion => {
-                    io_bus
-                        .insert(dev.clone(), bar.addr(), bar.size())
-                        .map_err(PciRootError::PioInsert)?;
+                    if io_enabled && !mapping.io_enabled {
+                        mapping
+                            .io_bus
+                            .insert(mapping.dev.clone(), bar.addr, bar.size)
+                            .map_err(PciRootError::PioInsert)?;
+                    } else if !io_enabled && mapping.io_enabled {
+                        let _ = mapping.io_bus.remove(bar.addr);
+                    }
                 }
                 PciBarRegionType::Memory32BitRegion | PciBarRegionType::Memory64BitRegion => {
-                    mmio_bus
-                        .insert(dev.clone(), bar.addr(), bar.size())
-                        .map_err(PciRootError::MmioInsert)?;
+                    if memory_enabled && !mapping.memory_enabled {
+                        mapping
+                            .mmio_bus
+                            .insert(mapping.dev.clone(), bar.addr, bar.size)
+                            .map_err(PciRootError::MmioInsert)?;
+                    } else if !memory_enabled && mapping.memory_enabled {
+                        let _ = mapping.mmio_bus.remove(bar.addr);
+                    }
                 }
             }
         }
+
+        mapping.io_enabled = io_enabled;
+        mapping.memory_enabled = memory_enabled;
+
         Ok(())
     }
 
-    pub fn add_device(&mut self, device_id: u32, device: Arc<Mutex<dyn PciDevice>>) -> Result<()> {
-        self.devices.insert(device_id, device);
+    /// Add `device` at `address`.
+    pub fn add_device(
+        &mut self,
+        address: PciAddress,
+        device: Arc<Mutex<dyn PciDevice>>,
+    ) -> Result<()> {
+        self.devices.insert(address, device);
+
         Ok(())
     }
 
+    /// Whether slot `address.dev` currently has more than one function
+    /// occupied, regardless of the order functions were added in.
+    fn has_multiple_functions(&self, address: PciAddress) -> bool {
+        (0..PCI_FUNCTIONS_PER_DEVICE as u8)
+            .filter(|&func| {
+                self.devices.contains_key(&PciAddress {
+                    bus: address.bus,
+                    dev: address.dev,
+                    func,
+                })
+            })
+            .count()
+            > 1
+    }
+
     pub fn remove_by_device(&mut self, device: &Arc<Mutex<dyn PciDevice>>) -> Result<()> {
         self.devices.retain(|_, dev| !Arc::ptr_eq(dev, device));
         Ok(())
     }
 
-    pub fn next_device_id(&mut self) -> Result<u32> {
+    /// Returns the address of the next free device slot on this bus, at
+    /// function 0.
+    pub fn next_device_id(&mut self) -> Result<PciAddress> {
         for (idx, device_id) in self.device_ids.iter_mut().enumerate() {
             if !(*device_id) {
                 *device_id = true;
-                return Ok(idx as u32);
+                return Ok(PciAddress {
+                    bus: 0,
+                    dev: idx as u8,
+                    func: 0,
+                });
+            }
+        }
+
+        Err(PciRootError::NoPciDeviceSlotAvailable)
+    }
+
+    /// Returns the address of the next free function on the device slot
+    /// `dev`, for attaching additional functions (e.g. multi-queue or
+    /// composite devices) to a device that already occupies function 0.
+    pub fn next_function_id(&self, dev: u8) -> Result<PciAddress> {
+        for func in 0..PCI_FUNCTIONS_PER_DEVICE as u8 {
+            let address = PciAddress { bus: 0, dev, func };
+            if !self.devices.contains_key(&address) {
+                return Ok(address);
             }
         }
 
@@ -198,6 +405,246 @@ impl PciBus {
             Err(PciRootError::InvalidPciDeviceSlot(id))
         }
     }
+
+    /// Read config register `register` of the device at `address`, routing
+    /// through a registered bridge when `address.bus` isn't this bus.
+    /// Returns `0xffff_ffff` if no bus or device is registered there.
+    ///
+    /// The header-type register's multi-function bit is synthesized here,
+    /// from whether `address`'s slot currently has more than one function
+    /// occupied, rather than being written into the device's own config
+    /// space: the header-type byte is read-only from the guest's
+    /// perspective, so a write attempting to set it is silently dropped by
+    /// `PciConfiguration`'s writable-bits mask and would never be reflected
+    /// back on a later read.
+    pub fn read_config_register(&self, address: PciAddress, register: usize) -> u32 {
+        if address.bus == 0 {
+            return self.devices.get(&address).map_or(0xffff_ffff, |d| {
+                let value = d.lock().unwrap().read_config_register(register);
+                if register == PCI_CONFIG_HEADER_TYPE_REG && self.has_multiple_functions(address) {
+                    value | HEADER_TYPE_MULTIFUNCTION_MASK
+                } else {
+                    value
+                }
+            });
+        }
+
+        self.resolve_bus(address.bus).map_or(0xffff_ffff, |bus| {
+            let child_address = PciAddress { bus: 0, ..address };
+            bus.lock()
+                .unwrap()
+                .read_config_register(child_address, register)
+        })
+    }
+
+    /// Write `data` at `offset` into config register `register` of the
+    /// device at `address`, routing through a registered bridge when
+    /// `address.bus` isn't this bus. Moves any reprogrammed BAR through
+    /// `device_reloc`, and re-keys `buses` if the write changed a bridge's
+    /// secondary-bus register.
+    pub fn write_config_register(
+        &mut self,
+        address: PciAddress,
+        register: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        if address.bus != 0 {
+            let bus = self.resolve_bus(address.bus)?;
+            let child_address = PciAddress { bus: 0, ..address };
+            return bus
+                .lock()
+                .unwrap()
+                .write_config_register(child_address, register, offset, data);
+        }
+
+        let d = self.devices.get(&address).cloned()?;
+        let mut device = d.lock().unwrap();
+
+        let old_secondary_bus = device
+            .as_any_mut()
+            .downcast_mut::<PciBridge>()
+            .map(PciBridge::secondary_bus);
+
+        let (bar_reprogram, ret) = device.write_config_register(register, offset, data);
+
+        for params in &bar_reprogram {
+            if let Err(e) = self.device_reloc.move_bar(
+                params.old_base,
+                params.new_base,
+                params.len,
+                device.deref_mut(),
+                params.region_type,
+            ) {
+                error!(
+                    "Failed moving device BAR: {}: 0x{:x}->0x{:x}(0x{:x})",
+                    e, params.old_base, params.new_base, params.len
+                );
+                continue;
+            }
+
+            // Keep `bar_mappings` pointing at the BAR's new address: it's
+            // only consulted when the COMMAND register is next toggled
+            // (`apply_command_register`), so without this a BAR moved while
+            // decoding was disabled would come back at its stale, pre-move
+            // address the next time the guest enables it. If decoding is
+            // currently enabled for this region, move the live bus insertion
+            // too instead of waiting for a COMMAND write that may never come.
+            if let Some(mapping) = self.bar_mappings.get_mut(&address) {
+                if let Some(bar) = mapping
+                    .bars
+                    .iter_mut()
+                    .find(|bar| bar.addr == params.old_base)
+                {
+                    bar.addr = params.new_base;
+
+                    let enabled = match params.region_type {
+                        PciBarRegionType::IoRegion => mapping.io_enabled,
+                        PciBarRegionType::Memory32BitRegion
+                        | PciBarRegionType::Memory64BitRegion => mapping.memory_enabled,
+                    };
+                    if enabled {
+                        let bus = match params.region_type {
+                            PciBarRegionType::IoRegion => &mapping.io_bus,
+                            PciBarRegionType::Memory32BitRegion
+                            | PciBarRegionType::Memory64BitRegion => &mapping.mmio_bus,
+                        };
+                        let _ = bus.remove(params.old_base);
+                        let _ = bus.insert(mapping.dev.clone(), params.new_base, params.len);
+                    }
+                }
+            }
+        }
+
+        let new_secondary_bus = old_secondary_bus.and_then(|_| {
+            device
+                .as_any_mut()
+                .downcast_mut::<PciBridge>()
+                .map(PciBridge::secondary_bus)
+        });
+        let new_command =
+            (register == COMMAND_REG).then(|| device.read_config_register(COMMAND_REG));
+
+        drop(device);
+
+        if let (Some(old_secondary_bus), Some(new_secondary_bus)) =
+            (old_secondary_bus, new_secondary_bus)
+        {
+            self.rekey_bus(old_secondary_bus, new_secondary_bus);
+        }
+
+        if let Some(command) = new_command {
+            let _ = self.apply_command_register(
+                address,
+                command & COMMAND_REG_IO_SPACE_MASK != 0,
+                command & COMMAND_REG_MEMORY_SPACE_MASK != 0,
+            );
+        }
+
+        ret
+    }
+
+    /// Capture a snapshot of this bus: every device's config-space
+    /// registers, the slot-allocation bitmap, and (recursively) any
+    /// secondary buses reachable through a bridge. Devices are addressed
+    /// locally (`address.bus == 0`), matching how `devices` keys them.
+    pub fn snapshot(&self) -> PciBusSnapshot {
+        let mut devices: Vec<PciDeviceSnapshot> = self
+            .devices
+            .iter()
+            .map(|(&address, device)| {
+                let mut device = device.lock().unwrap();
+                let registers = (0..PCI_CONFIG_SPACE_REGISTERS)
+                    .map(|reg| device.read_config_register(reg))
+                    .collect();
+                PciDeviceSnapshot { address, registers }
+            })
+            .collect();
+        devices.sort_by_key(|d| d.address);
+
+        let buses = self
+            .buses
+            .iter()
+            .map(|(&bus_number, bus)| (bus_number, bus.lock().unwrap().snapshot()))
+            .collect();
+
+        PciBusSnapshot {
+            devices,
+            device_ids: self.device_ids.clone(),
+            buses,
+        }
+    }
+
+    /// Restore device config-space registers and the slot-allocation bitmap
+    /// from `snapshot`. Devices (and bridges) must already be attached at
+    /// their saved addresses, mirroring how they were originally added via
+    /// `add_device`/`add_bridge`; this replays their register writes,
+    /// including BAR reprogramming through `device_reloc`, so relocated BARs
+    /// land at their saved addresses.
+    ///
+    /// The COMMAND register is replayed last for each device, after every
+    /// other register (including its BARs): `write_config_register` re-applies
+    /// the I/O and memory decoders as soon as COMMAND is written, so writing
+    /// it before the BARs are in their saved place would insert/remove
+    /// whatever stale addresses happened to be live at that point instead of
+    /// the restored ones. Any barriers returned along the way are collected
+    /// rather than discarded, for the caller to wait on.
+    pub fn restore(&mut self, snapshot: PciBusSnapshot) -> Result<Vec<Arc<Barrier>>> {
+        self.device_ids = snapshot.device_ids;
+
+        let mut barriers = Vec::new();
+
+        for device_snapshot in &snapshot.devices {
+            let mut command_write = None;
+            for (reg, value) in device_snapshot.registers.iter().enumerate() {
+                if reg == COMMAND_REG {
+                    command_write = Some(value);
+                    continue;
+                }
+                if let Some(barrier) =
+                    self.write_config_register(device_snapshot.address, reg, 0, &value.to_le_bytes())
+                {
+                    barriers.push(barrier);
+                }
+            }
+            if let Some(value) = command_write {
+                if let Some(barrier) = self.write_config_register(
+                    device_snapshot.address,
+                    COMMAND_REG,
+                    0,
+                    &value.to_le_bytes(),
+                ) {
+                    barriers.push(barrier);
+                }
+            }
+        }
+
+        for (bus_number, bus_snapshot) in snapshot.buses {
+            if let Some(bus) = self.resolve_bus(bus_number) {
+                barriers.extend(bus.lock().unwrap().restore(bus_snapshot)?);
+            }
+        }
+
+        Ok(barriers)
+    }
+}
+
+/// A point-in-time snapshot of one device's config-space registers, keyed by
+/// the local (bus 0) address it was registered at.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PciDeviceSnapshot {
+    pub address: PciAddress,
+    pub registers: Vec<u32>,
+}
+
+/// A point-in-time snapshot of a `PciBus`: its devices' config spaces, its
+/// slot-allocation bitmap, and any secondary buses reachable through a
+/// bridge attached to it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PciBusSnapshot {
+    pub devices: Vec<PciDeviceSnapshot>,
+    pub device_ids: Vec<bool>,
+    pub buses: Vec<(u8, PciBusSnapshot)>,
 }
 
 pub struct PciConfigIo {
@@ -220,28 +667,14 @@ impl PciConfigIo {
             return 0xffff_ffff;
         }
 
-        let (bus, device, function, register) =
-            parse_io_config_address(self.config_address & !0x8000_0000);
-
-        // Only support one bus.
-        if bus != 0 {
-            return 0xffff_ffff;
-        }
-
-        // Don't support multi-function devices.
-        if function > 0 {
-            return 0xffff_ffff;
-        }
+        let (address, register) =
+            PciAddress::from_cam_register(self.config_address & !0x8000_0000);
 
         self.pci_bus
             .as_ref()
             .lock()
             .unwrap()
-            .devices
-            .get(&(device as u32))
-            .map_or(0xffff_ffff, |d| {
-                d.lock().unwrap().read_config_register(register)
-            })
+            .read_config_register(address, register)
     }
 
     pub fn config_space_write(&mut self, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
@@ -254,41 +687,14 @@ impl PciConfigIo {
             return None;
         }
 
-        let (bus, device, _function, register) =
-            parse_io_config_address(self.config_address & !0x8000_0000);
-
-        // Only support one bus.
-        if bus != 0 {
-            return None;
-        }
-
-        let pci_bus = self.pci_bus.as_ref().lock().unwrap();
-        if let Some(d) = pci_bus.devices.get(&(device as u32)) {
-            let mut device = d.lock().unwrap();
-
-            // Update the register value
-            let (bar_reprogram, ret) = device.write_config_register(register, offset, data);
-
-            // Move the device's BAR if needed
-            for params in &bar_reprogram {
-                if let Err(e) = pci_bus.device_reloc.move_bar(
-                    params.old_base,
-                    params.new_base,
-                    params.len,
-                    device.deref_mut(),
-                    params.region_type,
-                ) {
-                    error!(
-                        "Failed moving device BAR: {}: 0x{:x}->0x{:x}(0x{:x})",
-                        e, params.old_base, params.new_base, params.len
-                    );
-                }
-            }
+        let (address, register) =
+            PciAddress::from_cam_register(self.config_address & !0x8000_0000);
 
-            ret
-        } else {
-            None
-        }
+        self.pci_bus
+            .as_ref()
+            .lock()
+            .unwrap()
+            .write_config_register(address, register, offset, data)
     }
 
     fn set_config_address(&mut self, offset: u64, data: &[u8]) {
@@ -309,6 +715,30 @@ impl PciConfigIo {
         };
         self.config_address = (self.config_address & !mask) | value;
     }
+
+    /// Snapshot the CONFIG_ADDRESS latch along with the whole PCI topology
+    /// reachable from this mechanism.
+    pub fn snapshot(&self) -> PciConfigIoSnapshot {
+        PciConfigIoSnapshot {
+            config_address: self.config_address,
+            bus: self.pci_bus.lock().unwrap().snapshot(),
+        }
+    }
+
+    /// Restore the CONFIG_ADDRESS latch and replay `snapshot.bus` onto the
+    /// already-rebuilt PCI topology.
+    pub fn restore(&mut self, snapshot: PciConfigIoSnapshot) -> Result<Vec<Arc<Barrier>>> {
+        self.config_address = snapshot.config_address;
+        self.pci_bus.lock().unwrap().restore(snapshot.bus)
+    }
+}
+
+/// A point-in-time snapshot of a `PciConfigIo`: its CONFIG_ADDRESS latch plus
+/// the `PciBus` topology it drives.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PciConfigIoSnapshot {
+    pub config_address: u32,
+    pub bus: PciBusSnapshot,
 }
 
 impl BusDevice for PciConfigIo {
@@ -358,21 +788,12 @@ impl PciConfigMmio {
     }
 
     fn config_space_read(&self, config_address: u32) -> u32 {
-        let (bus, device, _function, register) = parse_mmio_config_address(config_address);
-
-        // Only support one bus.
-        if bus != 0 {
-            return 0xffff_ffff;
-        }
+        let (address, register) = PciAddress::from_ecam_register(config_address);
 
         self.pci_bus
             .lock()
             .unwrap()
-            .devices
-            .get(&(device as u32))
-            .map_or(0xffff_ffff, |d| {
-                d.lock().unwrap().read_config_register(register)
-            })
+            .read_config_register(address, register)
     }
 
     fn config_space_write(&mut self, config_address: u32, offset: u64, data: &[u8]) {
@@ -380,36 +801,23 @@ impl PciConfigMmio {
             return;
         }
 
-        let (bus, device, _function, register) = parse_mmio_config_address(config_address);
+        let (address, register) = PciAddress::from_ecam_register(config_address);
 
-        // Only support one bus.
-        if bus != 0 {
-            return;
-        }
+        self.pci_bus
+            .lock()
+            .unwrap()
+            .write_config_register(address, register, offset, data);
+    }
 
-        let pci_bus = self.pci_bus.lock().unwrap();
-        if let Some(d) = pci_bus.devices.get(&(device as u32)) {
-            let mut device = d.lock().unwrap();
-
-            // Update the register value
-            let (bar_reprogram, _) = device.write_config_register(register, offset, data);
-
-            // Move the device's BAR if needed
-            for params in &bar_reprogram {
-                if let Err(e) = pci_bus.device_reloc.move_bar(
-                    params.old_base,
-                    params.new_base,
-                    params.len,
-                    device.deref_mut(),
-                    params.region_type,
-                ) {
-                    error!(
-                        "Failed moving device BAR: {}: 0x{:x}->0x{:x}(0x{:x})",
-                        e, params.old_base, params.new_base, params.len
-                    );
-                }
-            }
-        }
+    /// `PciConfigMmio` carries no state beyond the shared `PciBus`; these
+    /// exist for symmetry with `PciConfigIo` so the VMM's snapshot subsystem
+    /// can treat either config mechanism uniformly.
+    pub fn snapshot(&self) -> PciBusSnapshot {
+        self.pci_bus.lock().unwrap().snapshot()
+    }
+
+    pub fn restore(&mut self, snapshot: PciBusSnapshot) -> Result<Vec<Arc<Barrier>>> {
+        self.pci_bus.lock().unwrap().restore(snapshot)
     }
 }
 
@@ -441,46 +849,134 @@ impl BusDevice for PciConfigMmio {
     }
 }
 
-fn shift_and_mask(value: u32, offset: usize, mask: u32) -> usize {
-    ((value >> offset) & mask) as usize
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::BRIDGE_BUS_REGISTER;
+
+    struct NoopDeviceRelocation;
+
+    impl DeviceRelocation for NoopDeviceRelocation {
+        fn move_bar(
+            &self,
+            _old_base: u64,
+            _new_base: u64,
+            _len: u64,
+            _pci_dev: &mut dyn PciDevice,
+            _region_type: PciBarRegionType,
+        ) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
 
-// Parse the MMIO address offset to a (bus, device, function, register) tuple.
-// See section 7.2.2 PCI Express Enhanced Configuration Access Mechanism (ECAM)
-// from the Pci Express Base Specification Revision 5.0 Version 1.0.
-fn parse_mmio_config_address(config_address: u32) -> (usize, usize, usize, usize) {
-    const BUS_NUMBER_OFFSET: usize = 20;
-    const BUS_NUMBER_MASK: u32 = 0x00ff;
-    const DEVICE_NUMBER_OFFSET: usize = 15;
-    const DEVICE_NUMBER_MASK: u32 = 0x1f;
-    const FUNCTION_NUMBER_OFFSET: usize = 12;
-    const FUNCTION_NUMBER_MASK: u32 = 0x07;
-    const REGISTER_NUMBER_OFFSET: usize = 2;
-    const REGISTER_NUMBER_MASK: u32 = 0x3ff;
-
-    (
-        shift_and_mask(config_address, BUS_NUMBER_OFFSET, BUS_NUMBER_MASK),
-        shift_and_mask(config_address, DEVICE_NUMBER_OFFSET, DEVICE_NUMBER_MASK),
-        shift_and_mask(config_address, FUNCTION_NUMBER_OFFSET, FUNCTION_NUMBER_MASK),
-        shift_and_mask(config_address, REGISTER_NUMBER_OFFSET, REGISTER_NUMBER_MASK),
-    )
-}
+    fn new_bus() -> PciBus {
+        PciBus::new(PciRoot::new(None), Arc::new(NoopDeviceRelocation))
+    }
+
+    fn new_bridge(secondary_bus: u8) -> Arc<Mutex<PciBridge>> {
+        Arc::new(Mutex::new(PciBridge::new(0, secondary_bus, secondary_bus, None)))
+    }
+
+    #[test]
+    fn multifunction_bit_reflects_occupied_functions_regardless_of_add_order() {
+        let mut bus = new_bus();
+        let func0 = PciAddress::new(0, 1, 0).unwrap();
+        let func1 = PciAddress::new(0, 1, 1).unwrap();
+
+        bus.add_device(func0, Arc::new(Mutex::new(PciRoot::new(None))))
+            .unwrap();
+        assert_eq!(
+            bus.read_config_register(func0, PCI_CONFIG_HEADER_TYPE_REG)
+                & HEADER_TYPE_MULTIFUNCTION_MASK,
+            0
+        );
+
+        bus.add_device(func1, Arc::new(Mutex::new(PciRoot::new(None))))
+            .unwrap();
+        assert_ne!(
+            bus.read_config_register(func0, PCI_CONFIG_HEADER_TYPE_REG)
+                & HEADER_TYPE_MULTIFUNCTION_MASK,
+            0
+        );
+        // The bit reflects live occupancy of the slot, not which address was
+        // queried or the order functions were added in.
+        assert_ne!(
+            bus.read_config_register(func1, PCI_CONFIG_HEADER_TYPE_REG)
+                & HEADER_TYPE_MULTIFUNCTION_MASK,
+            0
+        );
+    }
+
+    #[test]
+    fn resolve_bus_walks_nested_bridges() {
+        let mut root = new_bus();
+        let child = Arc::new(Mutex::new(new_bus()));
+        let grandchild = Arc::new(Mutex::new(new_bus()));
+
+        let bridge_to_child = new_bridge(1);
+        root.add_bridge(
+            PciAddress::new(0, 1, 0).unwrap(),
+            bridge_to_child,
+            child.clone(),
+        )
+        .unwrap();
 
-// Parse the CONFIG_ADDRESS register to a (bus, device, function, register) tuple.
-fn parse_io_config_address(config_address: u32) -> (usize, usize, usize, usize) {
-    const BUS_NUMBER_OFFSET: usize = 16;
-    const BUS_NUMBER_MASK: u32 = 0x00ff;
-    const DEVICE_NUMBER_OFFSET: usize = 11;
-    const DEVICE_NUMBER_MASK: u32 = 0x1f;
-    const FUNCTION_NUMBER_OFFSET: usize = 8;
-    const FUNCTION_NUMBER_MASK: u32 = 0x07;
-    const REGISTER_NUMBER_OFFSET: usize = 2;
-    const REGISTER_NUMBER_MASK: u32 = 0x3f;
-
-    (
-        shift_and_mask(config_address, BUS_NUMBER_OFFSET, BUS_NUMBER_MASK),
-        shift_and_mask(config_address, DEVICE_NUMBER_OFFSET, DEVICE_NUMBER_MASK),
-        shift_and_mask(config_address, FUNCTION_NUMBER_OFFSET, FUNCTION_NUMBER_MASK),
-        shift_and_mask(config_address, REGISTER_NUMBER_OFFSET, REGISTER_NUMBER_MASK),
-    )
+        let bridge_to_grandchild = new_bridge(2);
+        child
+            .lock()
+            .unwrap()
+            .add_bridge(
+                PciAddress::new(0, 1, 0).unwrap(),
+                bridge_to_grandchild,
+                grandchild.clone(),
+            )
+            .unwrap();
+
+        assert!(root.resolve_bus(1).is_some());
+        assert!(
+            root.resolve_bus(2).is_some(),
+            "bus behind a grandchild bridge must still resolve"
+        );
+        assert!(root.resolve_bus(3).is_none());
+    }
+
+    #[test]
+    fn rekeying_a_bridges_secondary_bus_moves_the_resolved_bus() {
+        let mut root = new_bus();
+        let child = Arc::new(Mutex::new(new_bus()));
+        let bridge_address = PciAddress::new(0, 1, 0).unwrap();
+        let bridge = new_bridge(5);
+
+        root.add_bridge(bridge_address, bridge, child).unwrap();
+        assert!(root.resolve_bus(5).is_some());
+
+        let bus_numbers: u32 = (9u32 << 8) | 9;
+        root.write_config_register(
+            bridge_address,
+            BRIDGE_BUS_REGISTER,
+            0,
+            &bus_numbers.to_le_bytes(),
+        );
+
+        assert!(root.resolve_bus(5).is_none());
+        assert!(root.resolve_bus(9).is_some());
+    }
+
+    #[test]
+    fn snapshot_restore_round_trips_device_registers() {
+        let mut bus = new_bus();
+        let root_address = PciAddress::default();
+
+        bus.write_config_register(root_address, COMMAND_REG, 0, &3u32.to_le_bytes());
+        let snapshot = bus.snapshot();
+
+        let mut restored = new_bus();
+        restored.restore(snapshot).unwrap();
+
+        assert_eq!(
+            restored.read_config_register(root_address, COMMAND_REG) & 0x3,
+            3
+        );
+    }
 }
+